@@ -0,0 +1,16 @@
+//! error-chain based errors used throughout rexpect
+
+error_chain! {
+    foreign_links {
+        Io(::std::io::Error);
+        Nix(::nix::Error);
+    }
+
+    errors {
+        /// the read deadline elapsed before a match was found
+        Timeout(expected: String, got: Vec<u8>, timeout_ms: u64) {
+            description("timeout while waiting for pattern")
+            display("timeout ({} ms) while waiting for {:?}, got {:?}", timeout_ms, expected, got)
+        }
+    }
+}
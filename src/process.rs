@@ -0,0 +1,49 @@
+//! fork a process connected to a new pty
+
+use std::fs::File;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use nix::pty::{forkpty, Winsize};
+use nix::unistd::{ForkResult, Pid};
+use errors::*;
+use session::PtySize;
+
+/// a child process forked onto a new pty, as used internally by `spawn`
+pub struct PtyProcess {
+    pub pty: File,
+    pub child_pid: Pid,
+}
+
+impl PtyProcess {
+    pub fn new(command: Command) -> Result<Self> {
+        PtyProcess::new_with_size(command, None)
+    }
+
+    /// fork `command` onto a new pty, applying `size` to it via
+    /// `TIOCSWINSZ` before the child execs so that programs which query
+    /// the window size at startup see the right value immediately
+    pub fn new_with_size(mut command: Command, size: Option<PtySize>) -> Result<Self> {
+        let winsize = size.map(|s| {
+            Winsize {
+                ws_row: s.rows,
+                ws_col: s.cols,
+                ws_xpixel: s.pixel_width,
+                ws_ypixel: s.pixel_height,
+            }
+        });
+        match unsafe { forkpty(winsize.as_ref(), None) }.chain_err(|| "failed to fork pty")? {
+            ::nix::pty::ForkptyResult { fork_result: ForkResult::Child, .. } => {
+                let e = command.exec();
+                // exec() only returns on error
+                panic!("failed to exec child process: {}", e);
+            }
+            ::nix::pty::ForkptyResult { master, fork_result: ForkResult::Parent { child } } => {
+                Ok(PtyProcess {
+                       pty: unsafe { File::from_raw_fd(master) },
+                       child_pid: child,
+                   })
+            }
+        }
+    }
+}
@@ -1,21 +1,68 @@
 //! Main module of rexpect: start new process and interact with it
 
 use process::PtyProcess;
-use std::io::{BufReader, LineWriter};
-use std::ffi::OsStr;
+use std::io::{self, LineWriter};
 use std::fs::File;
 use std::process::Command;
-use std::os::unix::io::{FromRawFd, AsRawFd};
+use std::os::unix::io::{FromRawFd, AsRawFd, RawFd};
 use std::io::prelude::*;
+use std::thread;
+use std::time::{Duration, Instant};
 use nix::sys::{wait, signal};
+use nix::sys::select::{select, FdSet};
+use nix::sys::termios::{self, SetArg};
+use nix::sys::time::{TimeVal, TimeValLike};
 use nix::unistd;
+use regex::bytes::Regex;
 use errors::*; // load error-chain
 
+/// the pty signals the other end has gone away by returning EIO on read,
+/// rather than a regular zero-length read
+const EIO: i32 = nix::libc::EIO;
+
+/// the size of a pty's terminal window, set with the `TIOCSWINSZ` ioctl
+#[derive(Debug, Clone, Copy)]
+pub struct PtySize {
+    pub rows: u16,
+    pub cols: u16,
+    pub pixel_width: u16,
+    pub pixel_height: u16,
+}
+
+impl Default for PtySize {
+    /// a conventional 80x24 terminal with no pixel size (a freshly
+    /// allocated pty with no size applied reports 0x0)
+    fn default() -> Self {
+        PtySize {
+            rows: 24,
+            cols: 80,
+            pixel_width: 0,
+            pixel_height: 0,
+        }
+    }
+}
+
+ioctl_write_ptr_bad!(tiocswinsz, nix::libc::TIOCSWINSZ, nix::libc::winsize);
+
 /// Interact with a process with read/write/signals, etc.
 pub struct PtySession {
     process: PtyProcess,
     writer: LineWriter<File>,
-    reader: BufReader<File>,
+    /// read directly off the fd rather than through a `BufReader`: we
+    /// `select()` on this same fd before reading (whenever a timeout is
+    /// set, and always in `interact`), and a `BufReader`
+    /// can silently swallow more than it hands back, leaving bytes
+    /// sitting in its private buffer while `select()` sees (and waits on)
+    /// an empty kernel queue
+    reader: File,
+    /// bytes read from the child but not yet consumed by an exp_* call.
+    /// kept raw rather than decoded, since a multi-byte UTF-8 sequence can
+    /// straddle two reads and would otherwise get corrupted by decoding
+    /// each chunk on its own before the full sequence has arrived
+    buffer: Vec<u8>,
+    /// how long an exp_* call is willing to wait for its match before
+    /// giving up; `None` means wait forever
+    timeout: Option<Duration>,
 }
 
 impl PtySession {
@@ -23,6 +70,101 @@ impl PtySession {
         self.writer.write_all(line.as_bytes()).chain_err(|| "cannot write line to process")
     }
 
+    /// set (or clear, with `None`) the deadline that exp_* calls will wait
+    /// for a match before returning `ErrorKind::Timeout`
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// wait until we see `needle` in the output of the process, then
+    /// consume everything up to and including it
+    pub fn exp_string(&mut self, needle: &str) -> Result<()> {
+        let needle = needle.as_bytes();
+        let deadline = self.timeout.map(|t| Instant::now() + t);
+        loop {
+            let pos = if needle.is_empty() {
+                Some(0)
+            } else {
+                self.buffer.windows(needle.len()).position(|w| w == needle)
+            };
+            if let Some(pos) = pos {
+                self.buffer.drain(..pos + needle.len());
+                return Ok(());
+            }
+            if !self.read_into_buffer(deadline, &String::from_utf8_lossy(needle))? {
+                bail!("EOF before finding {:?} (read so far: {:?})",
+                      String::from_utf8_lossy(needle),
+                      String::from_utf8_lossy(&self.buffer));
+            }
+        }
+    }
+
+    /// wait until `regex` matches somewhere in the output of the process,
+    /// consume everything up to and including the match, and return the
+    /// matched text
+    pub fn exp_regex(&mut self, regex: &str) -> Result<String> {
+        let re = Regex::new(regex).chain_err(|| "invalid regex")?;
+        let deadline = self.timeout.map(|t| Instant::now() + t);
+        loop {
+            if let Some(m) = re.find(&self.buffer) {
+                let matched = String::from_utf8_lossy(&self.buffer[m.start()..m.end()]).into_owned();
+                self.buffer.drain(..m.end());
+                return Ok(matched);
+            }
+            if !self.read_into_buffer(deadline, regex)? {
+                bail!("EOF before matching {:?} (read so far: {:?})",
+                      regex,
+                      String::from_utf8_lossy(&self.buffer));
+            }
+        }
+    }
+
+    /// wait until the child closes its end of the pty
+    pub fn exp_eof(&mut self) -> Result<()> {
+        let deadline = self.timeout.map(|t| Instant::now() + t);
+        while self.read_into_buffer(deadline, "EOF")? {}
+        Ok(())
+    }
+
+    /// read up to 1024 bytes of what is currently available from the child
+    /// into `self.buffer`, with a single `read()` call.
+    ///
+    /// if `deadline` is set, first `select()` on the pty fd for the
+    /// remaining time budget and return `ErrorKind::Timeout` (carrying the
+    /// bytes read so far) if it elapses before data is ready.
+    ///
+    /// returns `Ok(false)` on EOF (a zero-length read, or the EIO a pty
+    /// returns once the child has closed its end) rather than an error, and
+    /// `Ok(true)` if at least one more byte might be available.
+    fn read_into_buffer(&mut self, deadline: Option<Instant>, waiting_for: &str) -> Result<bool> {
+        if let Some(deadline) = deadline {
+            let now = Instant::now();
+            let remaining = if now >= deadline { Duration::from_secs(0) } else { deadline - now };
+            let fd = self.reader.as_raw_fd();
+            let mut read_fds = FdSet::new();
+            read_fds.insert(fd);
+            let mut tv = TimeVal::milliseconds(duration_to_ms(remaining));
+            let n = select(fd + 1, Some(&mut read_fds), None, None, Some(&mut tv))
+                .chain_err(|| "select on pty fd failed")?;
+            if n == 0 {
+                return Err(ErrorKind::Timeout(waiting_for.into(),
+                                               self.buffer.clone(),
+                                               duration_to_ms(self.timeout.unwrap_or(remaining)) as u64)
+                                   .into());
+            }
+        }
+        let mut buf = [0u8; 1024];
+        match self.reader.read(&mut buf) {
+            Ok(0) => Ok(false),
+            Ok(n) => {
+                self.buffer.extend_from_slice(&buf[..n]);
+                Ok(true)
+            }
+            Err(ref e) if e.raw_os_error() == Some(EIO) => Ok(false),
+            Err(e) => Err(e).chain_err(|| "error while reading from process"),
+        }
+    }
+
     /// get status of child process, nonblocking
     ///
     /// # Example
@@ -52,18 +194,255 @@ impl PtySession {
             unistd::close(self.process.pty.as_raw_fd())
         ).chain_err(|| "failed to exit process")
     }
+
+    /// block until the child has exited, reaping it, and return its status
+    pub fn wait(&mut self) -> Result<wait::WaitStatus> {
+        wait::waitpid(self.process.child_pid, None).chain_err(|| "cannot wait for process")
+    }
+
+    /// ask the child to exit, escalating to `SIGKILL` if it ignores
+    /// `signal` for longer than `timeout`
+    ///
+    /// sends `signal` (commonly `SIGTERM` or `SIGHUP`) and polls `status()`
+    /// until either the child exits or `timeout` elapses; if it's still
+    /// alive at that point, sends `SIGKILL` and blocks on `wait()` to reap
+    /// it. either way, closes the pty and returns the final status so
+    /// callers can tell a clean exit from a kill with `exit_code()`.
+    pub fn exit_timeout(&mut self, signal: signal::Signal, timeout: Duration) -> Result<wait::WaitStatus> {
+        signal::kill(self.process.child_pid, signal).chain_err(|| "failed to signal process")?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            match self.status()? {
+                wait::WaitStatus::StillAlive => {
+                    if Instant::now() >= deadline {
+                        break;
+                    }
+                    thread::sleep(Duration::from_millis(20));
+                }
+                status => {
+                    unistd::close(self.process.pty.as_raw_fd()).chain_err(|| "failed to close pty")?;
+                    return Ok(status);
+                }
+            }
+        }
+        signal::kill(self.process.child_pid, signal::SIGKILL).chain_err(|| "failed to kill process")?;
+        let status = self.wait()?;
+        unistd::close(self.process.pty.as_raw_fd()).chain_err(|| "failed to close pty")?;
+        Ok(status)
+    }
+
+    /// resize the pty's terminal window via `TIOCSWINSZ`, for programs
+    /// (editors, pagers, readline) that query the window size and behave
+    /// differently depending on it
+    pub fn resize(&self, size: PtySize) -> Result<()> {
+        let winsize = nix::libc::winsize {
+            ws_row: size.rows,
+            ws_col: size.cols,
+            ws_xpixel: size.pixel_width,
+            ws_ypixel: size.pixel_height,
+        };
+        unsafe { tiocswinsz(self.process.pty.as_raw_fd(), &winsize) }
+            .chain_err(|| "failed to set window size")?;
+        Ok(())
+    }
+
+    /// shorthand for `resize` when only rows/cols matter
+    pub fn set_window_size(&self, rows: u16, cols: u16) -> Result<()> {
+        self.resize(PtySize { rows: rows, cols: cols, ..Default::default() })
+    }
+
+    /// hand control of the session to the user: copy bytes between the
+    /// controlling terminal and the child in both directions until the
+    /// child closes its end of the pty or stdin reaches EOF
+    ///
+    /// useful for automating the boring setup of a session and then
+    /// dropping into an interactive shell for the rest. the parent tty is
+    /// put into raw mode for the duration and restored afterwards, even
+    /// if this function returns early via `?`.
+    pub fn interact(&mut self) -> Result<()> {
+        let stdin_fd = io::stdin().as_raw_fd();
+        // `self.reader` is a plain `File`, not a `BufReader`, precisely so
+        // that this `select()` and the `read()` below agree on what's
+        // pending on `pty_fd` — a buffering reader could stash bytes from
+        // one read() past what it hands back, leaving select() to wait on
+        // a kernel queue that looks empty even though output is ready
+        let pty_fd = self.reader.as_raw_fd();
+        let _raw_mode = RawModeGuard::enable(stdin_fd)?;
+        let mut buf = [0u8; 1024];
+        loop {
+            let mut read_fds = FdSet::new();
+            read_fds.insert(stdin_fd);
+            read_fds.insert(pty_fd);
+            select(::std::cmp::max(stdin_fd, pty_fd) + 1, Some(&mut read_fds), None, None, None)
+                .chain_err(|| "select on stdin/pty failed")?;
+
+            if read_fds.contains(pty_fd) {
+                match self.reader.read(&mut buf) {
+                    Ok(0) => return Ok(()),
+                    Ok(n) => io::stdout().write_all(&buf[..n]).chain_err(|| "cannot write to stdout")?,
+                    Err(ref e) if e.raw_os_error() == Some(EIO) => return Ok(()),
+                    Err(e) => return Err(e).chain_err(|| "error while reading from process"),
+                }
+                io::stdout().flush().chain_err(|| "cannot flush stdout")?;
+            }
+            if read_fds.contains(stdin_fd) {
+                let n = unistd::read(stdin_fd, &mut buf).chain_err(|| "cannot read from stdin")?;
+                if n == 0 {
+                    return Ok(());
+                }
+                self.writer.write_all(&buf[..n]).chain_err(|| "cannot write to process")?;
+                self.writer.flush().chain_err(|| "cannot flush to process")?;
+            }
+        }
+    }
+}
+
+/// puts `fd` (expected to be a tty) into raw mode, restoring its original
+/// attributes when dropped so a panic or early return never leaves the
+/// user's shell unusable
+struct RawModeGuard {
+    fd: RawFd,
+    original: termios::Termios,
+}
+
+impl RawModeGuard {
+    fn enable(fd: RawFd) -> Result<RawModeGuard> {
+        let original = termios::tcgetattr(fd).chain_err(|| "cannot read terminal attributes")?;
+        let mut raw = original.clone();
+        termios::cfmakeraw(&mut raw);
+        termios::tcsetattr(fd, SetArg::TCSANOW, &raw).chain_err(|| "cannot set terminal to raw mode")?;
+        Ok(RawModeGuard { fd: fd, original: original })
+    }
+}
+
+impl Drop for RawModeGuard {
+    fn drop(&mut self) {
+        let _ = termios::tcsetattr(self.fd, SetArg::TCSANOW, &self.original);
+    }
+}
+
+/// convert a `WaitStatus` into an exit code, using the `128 + signal`
+/// convention `std::process::exit` callers and shells rely on to tell a
+/// normal exit from termination by signal. returns `None` if the child
+/// hasn't actually exited (e.g. `WaitStatus::StillAlive`).
+pub fn exit_code(status: wait::WaitStatus) -> Option<i32> {
+    match status {
+        wait::WaitStatus::Exited(_, code) => Some(code),
+        wait::WaitStatus::Signaled(_, sig, _) => Some(128 + sig as i32),
+        _ => None,
+    }
 }
 
-pub fn spawn<S: AsRef<OsStr>>(program: S) -> Result<PtySession> {
-    let command = Command::new(program);
-    let process = PtyProcess::new(command).chain_err(|| "couldn't start process")?;
+/// convert a `Duration` to whole milliseconds, rounding up so a non-zero
+/// remainder never gets truncated away into an immediate timeout
+fn duration_to_ms(d: Duration) -> i64 {
+    let ms = d.as_secs() * 1000 + (d.subsec_nanos() as u64 + 999_999) / 1_000_000;
+    ms as i64
+}
+
+/// split a shell-style command line into its program and arguments,
+/// honoring single quotes, double quotes and backslash escapes the way a
+/// shell would, so e.g. `"prog 'a b' c"` becomes `["prog", "a b", "c"]`.
+/// an unterminated quote runs to the end of the string rather than
+/// being an error
+fn split_command_line(program: &str) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut chars = program.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' if in_word => {
+                words.push(word.clone());
+                word.clear();
+                in_word = false;
+            }
+            ' ' | '\t' => {}
+            '\'' => {
+                in_word = true;
+                while let Some(c) = chars.next() {
+                    if c == '\'' {
+                        break;
+                    }
+                    word.push(c);
+                }
+            }
+            '"' => {
+                in_word = true;
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' if chars.peek().map_or(false, |&n| "\"\\$`".contains(n)) => {
+                            word.push(chars.next().unwrap())
+                        }
+                        c => word.push(c),
+                    }
+                }
+            }
+            '\\' => {
+                in_word = true;
+                if let Some(c) = chars.next() {
+                    word.push(c);
+                }
+            }
+            c => {
+                in_word = true;
+                word.push(c);
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    if words.is_empty() {
+        bail!("cannot spawn an empty command line");
+    }
+    Ok(words)
+}
+
+/// spawn a process from a shell-style command line, e.g. `"prog arg1 'arg 2'"`
+///
+/// the string is tokenized the way a shell would (honoring quoting and
+/// escapes) before being handed to `Command`, so `spawn("sleep 5")` really
+/// execs `sleep` with the argument `"5"` rather than a binary literally
+/// named `"sleep 5"`
+pub fn spawn<S: AsRef<str>>(program: S) -> Result<PtySession> {
+    spawn_with_size(program, None)
+}
+
+/// like `spawn`, but additionally applies `size` to the pty before the
+/// child gets a chance to query it
+pub fn spawn_with_size<S: AsRef<str>>(program: S, size: Option<PtySize>) -> Result<PtySession> {
+    let mut words = split_command_line(program.as_ref())?.into_iter();
+    let mut command = Command::new(words.next().expect("split_command_line never returns empty"));
+    command.args(words);
+    spawn_command_with_size(command, size)
+}
+
+/// spawn a process from an already-built `std::process::Command`, for
+/// callers that need full control over the program, arguments or
+/// environment rather than a shell-style string
+pub fn spawn_command(command: Command) -> Result<PtySession> {
+    spawn_command_with_size(command, None)
+}
+
+/// like `spawn_command`, but additionally applies `size` to the pty
+/// before the child gets a chance to query it
+pub fn spawn_command_with_size(command: Command, size: Option<PtySize>) -> Result<PtySession> {
+    // applying `size` inside `PtyProcess::new_with_size` (rather than
+    // calling `resize()` here once the session exists) ensures the pty's
+    // window size is set before the child execs, so programs that query
+    // it at startup (editors, pagers, readline) see the real size
+    let process = PtyProcess::new_with_size(command, size).chain_err(|| "couldn't start process")?;
     let f = unsafe { File::from_raw_fd(process.pty.as_raw_fd()) };
     let writer = LineWriter::new(f.try_clone().chain_err(|| "couldn't open write stream")?);
-    let reader = BufReader::new(f);
+    let reader = f;
     Ok(PtySession {
            process: process,
            writer: writer,
            reader: reader,
+           buffer: Vec::new(),
+           timeout: None,
        })
 }
 
@@ -82,4 +461,134 @@ mod tests {
         }().expect("could not execute");
     }
 
+    #[test]
+    fn test_expect_string() {
+        || -> Result<()> {
+            let mut s = spawn("cat")?;
+            s.send_line("hello, polly!\n")?;
+            s.exp_string("hello, polly!")?;
+            s.exit()?;
+            Ok(())
+        }().expect("could not execute");
+    }
+
+    #[test]
+    fn test_expect_string_timeout() {
+        || -> Result<()> {
+            let mut s = spawn("cat")?;
+            s.set_timeout(Some(Duration::from_millis(500)));
+            match s.exp_string("never going to appear") {
+                Err(Error(ErrorKind::Timeout(..), _)) => Ok(()),
+                Err(e) => Err(e),
+                Ok(_) => bail!("expected a timeout"),
+            }
+        }().expect("could not execute");
+    }
+
+    #[test]
+    fn test_spawn_with_args() {
+        || -> Result<()> {
+            let mut s = spawn("echo hello world")?;
+            s.exp_string("hello world")?;
+            s.exit()?;
+            Ok(())
+        }().expect("could not execute");
+    }
+
+    #[test]
+    fn test_split_command_line_quoting() {
+        assert_eq!(split_command_line("prog 'a b' c").unwrap(),
+                   vec!["prog", "a b", "c"]);
+        assert_eq!(split_command_line(r#"prog "a b" c"#).unwrap(),
+                   vec!["prog", "a b", "c"]);
+    }
+
+    #[test]
+    fn test_duration_to_ms_rounds_up() {
+        // a sub-millisecond remainder must still select() for at least
+        // 1ms, never 0 (which would poll and time out immediately)
+        assert_eq!(duration_to_ms(Duration::new(0, 500_000)), 1);
+        assert_eq!(duration_to_ms(Duration::new(0, 1_000_000)), 1);
+        assert_eq!(duration_to_ms(Duration::new(1, 1)), 1001);
+    }
+
+    #[test]
+    fn test_exp_string_finds_needle_past_one_read_worth_of_burst_output() {
+        // a burst bigger than read_into_buffer's 1024-byte scratch buffer
+        // must not make exp_string time out: once the whole burst has
+        // landed in the kernel, select() reports the fd ready each time
+        // and exp_string keeps reading 1024 bytes at a time until the
+        // needle turns up
+        || -> Result<()> {
+            let padding: String = ::std::iter::repeat('x').take(4096).collect();
+            let mut s = spawn("cat")?;
+            s.set_timeout(Some(Duration::from_secs(2)));
+            s.send_line(&padding)?;
+            s.send_line("needle-after-burst\n")?;
+            s.exp_string("needle-after-burst")?;
+            s.exit()?;
+            Ok(())
+        }().expect("could not execute");
+    }
+
+    #[test]
+    fn test_resize() {
+        || -> Result<()> {
+            let s = spawn_with_size("cat", Some(PtySize { rows: 40, cols: 100, ..Default::default() }))?;
+            s.set_window_size(50, 120)?;
+            s.exit()?;
+            Ok(())
+        }().expect("could not execute");
+    }
+
+    #[test]
+    fn test_exit_timeout_escalates_to_sigkill() {
+        || -> Result<()> {
+            // ignore SIGTERM so the graceful signal is a no-op and the
+            // 200ms timeout genuinely elapses, forcing the SIGKILL path
+            let mut s = spawn("sh -c 'trap \"\" TERM; while :; do sleep 1; done'")?;
+            let status = s.exit_timeout(signal::Signal::SIGTERM, Duration::from_millis(200))?;
+            match status {
+                wait::WaitStatus::Signaled(_, signal::Signal::SIGKILL, _) => Ok(()),
+                other => bail!("expected child to be killed by SIGKILL, got {:?}", other),
+            }
+        }().expect("could not execute");
+    }
+
+    #[test]
+    fn test_raw_mode_guard_restores_attrs() {
+        // exercise RawModeGuard directly against a real pty (standing in
+        // for a controlling terminal) rather than interact()'s stdin/stdout
+        // loop, which isn't driveable from a non-interactive test harness
+        || -> Result<()> {
+            let s = spawn("cat")?;
+            let fd = s.process.pty.as_raw_fd();
+            let before = termios::tcgetattr(fd)?;
+            let was_canonical = before.local_flags.contains(termios::LocalFlags::ICANON);
+
+            {
+                let _guard = RawModeGuard::enable(fd)?;
+                let raw = termios::tcgetattr(fd)?;
+                assert!(!raw.local_flags.contains(termios::LocalFlags::ICANON));
+            }
+            let after = termios::tcgetattr(fd)?;
+            assert_eq!(after.local_flags.contains(termios::LocalFlags::ICANON), was_canonical);
+
+            // the guard must also restore attrs when dropped during an
+            // early return / panic, not just on the happy path
+            let panicked = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+                let _guard = RawModeGuard::enable(fd).expect("enable raw mode");
+                panic!("forced error path to exercise Drop");
+            }))
+                .is_err();
+            assert!(panicked);
+            let after_panic = termios::tcgetattr(fd)?;
+            assert_eq!(after_panic.local_flags.contains(termios::LocalFlags::ICANON),
+                       was_canonical);
+
+            s.exit()?;
+            Ok(())
+        }().expect("could not execute");
+    }
+
 }
\ No newline at end of file